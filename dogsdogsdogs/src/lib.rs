@@ -0,0 +1,2 @@
+pub mod operators;
+pub mod plan;
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use timely::PartialOrder;
+use timely::dataflow::Scope;
+use timely::dataflow::channels::pact::{Pipeline, Exchange};
+use timely::dataflow::operators::Operator;
+
+use timely_sort::Unsigned;
+
+use differential_dataflow::{ExchangeData, Collection, AsCollection, Hashable};
+use differential_dataflow::lattice::Lattice;
+use differential_dataflow::operators::arrange::Arranged;
+use differential_dataflow::trace::{Cursor, TraceReader, BatchReader};
+
+/// Reports, for each prefix, how many extensions `arrangement` would propose for it, and
+/// retains the smallest count seen so far across repeated calls (along with the relation that
+/// produced it).
+///
+/// This is the counting step of a worst-case-optimal join (the Ngo-Re-Rudra bound): before any
+/// relation is asked to actually propose extensions, every constraining relation is asked how
+/// many candidates it has. Chaining one `count` call per relation and keeping only the minimum
+/// ensures the subsequent `propose` call (driven by the winning relation) cannot produce more
+/// tuples than the least selective relation allows, with the rest acting as validators.
+///
+/// Each prefix carries `(current_min_count, winning_relation_index)` alongside `P`. A count of
+/// zero means this relation has no extensions for the prefix, so the prefix cannot join to
+/// anything and is dropped. Ties for the minimum are broken in favor of the lowest
+/// `relation_index`: an equal count overwrites the winner only when `relation_index` is lower,
+/// whatever order the calls are chained in. Callers chain one `count` per constraining relation, seeding the initial
+/// `(current_min_count, winning_relation_index)` as `(usize::MAX, usize::MAX)`.
+///
+/// Unlike `propose_then`/`validate_then`, this is fixed to `R=isize` rather than generic over
+/// `Monoid`: picking the *smallest* count needs a total order with ordinary integer comparisons,
+/// which a bare `Monoid` doesn't provide, so the diff type is pinned to the same `isize` the rest
+/// of the crate uses for multiplicities instead of threading an extra `Ord` bound through.
+pub fn count<G, Tr, F, P>(
+    prefixes: &Collection<G, (P, usize, usize), isize>,
+    arrangement: Arranged<G, Tr>,
+    key_selector: F,
+    relation_index: usize,
+) -> Collection<G, (P, usize, usize), isize>
+where
+    G: Scope,
+    G::Timestamp: Lattice,
+    Tr: TraceReader<Time=G::Timestamp, R=isize>+Clone+'static,
+    Tr::Key: Ord+Hashable,
+    Tr::Val: Clone,
+    Tr::Batch: BatchReader<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::Cursor: Cursor<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    F: Fn(&P)->Tr::Key+Clone+'static,
+    P: ExchangeData,
+{
+    let count_stream = arrangement.stream;
+    let mut count_trace = Some(arrangement.trace);
+
+    let mut stash = HashMap::new();
+    let logic1 = key_selector.clone();
+    let logic2 = key_selector.clone();
+
+    let mut buffer1 = Vec::new();
+    let mut buffer2 = Vec::new();
+
+    let exchange = Exchange::new(move |update: &((P,usize,usize),G::Timestamp,isize)| {
+        logic1(&(update.0).0).hashed().as_u64()
+    });
+
+    prefixes.inner.binary_frontier(&count_stream, exchange, Pipeline, "Count", move |_,_| move |input1, input2, output| {
+
+        // drain the first input, stashing requests.
+        input1.for_each(|capability, data| {
+            data.swap(&mut buffer1);
+            stash.entry(capability.retain())
+                 .or_insert(Vec::new())
+                 .extend(buffer1.drain(..))
+        });
+
+        // advance the `distinguish_since` frontier to allow all merges.
+        input2.for_each(|_, batches| {
+            batches.swap(&mut buffer2);
+            for batch in buffer2.drain(..) {
+                if let Some(ref mut trace) = count_trace {
+                    trace.distinguish_since(batch.upper());
+                }
+            }
+        });
+
+        if let Some(ref mut trace) = count_trace {
+
+            for (capability, prefixes) in stash.iter_mut() {
+
+                // defer requests at incomplete times.
+                // NOTE: not all updates may be at complete times, but if this test fails then none of them are.
+                if !input2.frontier.less_equal(capability.time()) {
+
+                    let mut session = output.session(capability);
+
+                    // sort requests for in-order cursor traversal. could consolidate?
+                    prefixes.sort_by(|x,y| logic2(&(x.0).0).cmp(&logic2(&(y.0).0)));
+
+                    let (mut cursor, storage) = trace.cursor();
+
+                    for &mut (ref mut prefix, ref time, ref mut diff) in prefixes.iter_mut() {
+                        if !input2.frontier.less_equal(time) {
+
+                            let (ref p, ref mut min_count, ref mut min_index) = *prefix;
+                            let key = logic2(p);
+                            cursor.seek_key(&storage, &key);
+
+                            let mut count: isize = 0;
+                            if cursor.get_key(&storage) == Some(&key) {
+                                while let Some(_value) = cursor.get_val(&storage) {
+                                    cursor.map_times(&storage, |t, d| if t.less_equal(time) { count += d; });
+                                    cursor.step_val(&storage);
+                                }
+                                cursor.rewind_vals(&storage);
+                            }
+
+                            // count <= 0: this relation offers no extensions, so the prefix is a
+                            // dead end (a well-formed trace should never sum to a negative count
+                            // once the frontier has passed `time`, but the `<= 0` guard keeps the
+                            // `as usize` cast below from wrapping a corrupt count into a huge
+                            // `usize` that could spuriously "win" the race).
+                            if count > 0 {
+                                let count = count as usize;
+                                if count < *min_count || (count == *min_count && relation_index < *min_index) {
+                                    *min_count = count;
+                                    *min_index = relation_index;
+                                }
+                                session.give((prefix.clone(), time.clone(), *diff));
+                            }
+
+                            *diff = 0;
+                        }
+                    }
+
+                    prefixes.retain(|ptd| ptd.2 != 0);
+                }
+            }
+        }
+
+        // drop fully processed capabilities.
+        stash.retain(|_,prefixes| !prefixes.is_empty());
+
+        // advance the consolidation frontier (TODO: wierd lexicographic times!)
+        count_trace.as_mut().map(|trace| trace.advance_by(&input1.frontier().frontier()));
+
+        if input1.frontier().is_empty() && stash.is_empty() {
+            count_trace = None;
+        }
+
+    }).as_collection()
+}
@@ -0,0 +1,9 @@
+pub mod propose;
+pub mod count;
+pub mod validate;
+pub mod stash;
+
+pub use propose::{propose, propose_then, propose_then_with_stash, propose_upsert, propose_upsert_with_stash};
+pub use count::count;
+pub use validate::validate_then;
+pub use stash::{PrefixStash, VecStash, RegionStash};
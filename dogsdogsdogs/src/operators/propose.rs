@@ -14,6 +14,8 @@ use differential_dataflow::lattice::Lattice;
 use differential_dataflow::operators::arrange::Arranged;
 use differential_dataflow::trace::{Cursor, TraceReader, BatchReader};
 
+use super::stash::{PrefixStash, VecStash};
+
 pub fn propose<G, Tr, F, P>(
     prefixes: &Collection<G, P, Tr::R>,
     arrangement: Arranged<G, Tr>,
@@ -43,6 +45,10 @@ where
 /// This method takes a stream of prefixes and for each determines a
 /// key with `key_selector` and then proposes all pair af the prefix
 /// and values associated with the key in `arrangement`.
+///
+/// Prefixes are held in a `VecStash` while their time is incomplete. For workloads with very
+/// large numbers of in-flight prefixes and an expensive-to-move `P`, see
+/// [`propose_then_with_stash`], which takes the stash implementation as a type parameter.
 pub fn propose_then<G, Tr, F, P, O, S>(
     prefixes: &Collection<G, P, Tr::R>,
     arrangement: Arranged<G, Tr>,
@@ -62,11 +68,78 @@ where
     P: ExchangeData,
     O: Clone+'static,
     S: Fn(&P, &Tr::Val)->O+'static,
+{
+    propose_then_with_stash::<_, _, _, _, _, _, VecStash<P, G::Timestamp, Tr::R>>(
+        prefixes,
+        arrangement,
+        key_selector,
+        output_func,
+    )
+}
+
+/// Like [`propose_then`], but takes the per-capability prefix stash implementation as a type
+/// parameter `St: PrefixStash`. Pass `RegionStash` in place of the default `VecStash` for
+/// high-fanout joins with millions of in-flight prefixes, where `P` is expensive to move and the
+/// region-backed stash's index-based sorting pays for itself.
+pub fn propose_then_with_stash<G, Tr, F, P, O, S, St>(
+    prefixes: &Collection<G, P, Tr::R>,
+    arrangement: Arranged<G, Tr>,
+    key_selector: F,
+    output_func: S,
+) -> Collection<G, O, Tr::R>
+where
+    G: Scope,
+    G::Timestamp: Lattice,
+    Tr: TraceReader<Time=G::Timestamp>+Clone+'static,
+    Tr::Key: Ord+Hashable+Default,
+    Tr::Val: Clone,
+    Tr::Batch: BatchReader<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::Cursor: Cursor<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::R: Monoid+Mul<Output = Tr::R>+ExchangeData,
+    F: Fn(&P, &mut Tr::Key)+Clone+'static,
+    P: ExchangeData,
+    O: Clone+'static,
+    S: Fn(&P, &Tr::Val)->O+'static,
+    St: PrefixStash<P, G::Timestamp, Tr::R>+'static,
+{
+    // visits every value under a key, proposing each one as `propose_then`'s doc describes.
+    propose_cursor_walk::<_, _, _, _, _, _, St>(prefixes, arrangement, key_selector, output_func, "Propose", false)
+}
+
+/// Shared dataflow wiring behind [`propose_then_with_stash`] and [`propose_upsert_with_stash`]:
+/// both stash prefixes per capability, sort them for in-order cursor traversal, and for each walk
+/// `arrangement`'s values for the prefix's key, giving `(output_func(prefix, value), time, count *
+/// diff)` whenever that product is non-zero. They differ only in whether, once a non-zero product
+/// is found for a key, the walk keeps visiting that key's remaining values (`propose_then` gives
+/// an output for each of them, so `stop_at_first_match` is `false`) or stops there (`propose_upsert` assumes
+/// at most one value has non-zero multiplicity at any time, so it passes `true`).
+fn propose_cursor_walk<G, Tr, F, P, O, S, St>(
+    prefixes: &Collection<G, P, Tr::R>,
+    arrangement: Arranged<G, Tr>,
+    key_selector: F,
+    output_func: S,
+    operator_name: &'static str,
+    stop_at_first_match: bool,
+) -> Collection<G, O, Tr::R>
+where
+    G: Scope,
+    G::Timestamp: Lattice,
+    Tr: TraceReader<Time=G::Timestamp>+Clone+'static,
+    Tr::Key: Ord+Hashable+Default,
+    Tr::Val: Clone,
+    Tr::Batch: BatchReader<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::Cursor: Cursor<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::R: Monoid+Mul<Output = Tr::R>+ExchangeData,
+    F: Fn(&P, &mut Tr::Key)+Clone+'static,
+    P: ExchangeData,
+    O: Clone+'static,
+    S: Fn(&P, &Tr::Val)->O+'static,
+    St: PrefixStash<P, G::Timestamp, Tr::R>+'static,
 {
     let propose_stream = arrangement.stream;
     let mut propose_trace = Some(arrangement.trace);
 
-    let mut stash = HashMap::new();
+    let mut stash: HashMap<_, St> = HashMap::new();
     let logic1 = key_selector.clone();
     let logic2 = key_selector.clone();
 
@@ -81,14 +154,15 @@ where
 
     let mut key1: Tr::Key = Default::default();
     let mut key2: Tr::Key = Default::default();
-    prefixes.inner.binary_frontier(&propose_stream, exchange, Pipeline, "Propose", move |_,_| move |input1, input2, output| {
+    prefixes.inner.binary_frontier(&propose_stream, exchange, Pipeline, operator_name, move |_,_| move |input1, input2, output| {
 
         // drain the first input, stashing requests.
         input1.for_each(|capability, data| {
             data.swap(&mut buffer1);
-            stash.entry(capability.retain())
-                 .or_insert(Vec::new())
-                 .extend(buffer1.drain(..))
+            let entry = stash.entry(capability.retain()).or_insert_with(St::new);
+            for (prefix, time, diff) in buffer1.drain(..) {
+                entry.push(prefix, time, diff);
+            }
         });
 
         // advance the `distinguish_since` frontier to allow all merges.
@@ -113,17 +187,16 @@ where
 
                     // sort requests for in-order cursor traversal. could consolidate?
                     prefixes.sort_by(|x,y| {
-                        logic2(&x.0, &mut key1);
-                        logic2(&y.0, &mut key2);
+                        logic2(x, &mut key1);
+                        logic2(y, &mut key2);
                         key1.cmp(&key2)
                     });
 
                     let (mut cursor, storage) = trace.cursor();
 
-                    for &mut (ref prefix, ref time, ref mut diff) in prefixes.iter_mut() {
+                    prefixes.for_each_mut(|prefix, time, diff| {
                         if !input2.frontier.less_equal(time) {
                             logic2(prefix, &mut key1);
-                            // let key = logic2(prefix);
                             cursor.seek_key(&storage, &key1);
                             if cursor.get_key(&storage) == Some(&key1) {
                                 while let Some(value) = cursor.get_val(&storage) {
@@ -131,7 +204,10 @@ where
                                     cursor.map_times(&storage, |t, d| if t.less_equal(time) { count += d; });
                                     let prod = count * diff.clone();
                                     if !prod.is_zero() {
-                                        session.give((output_func(&prefix, &value), time.clone(), prod));
+                                        session.give((output_func(prefix, value), time.clone(), prod));
+                                        if stop_at_first_match {
+                                            break;
+                                        }
                                     }
                                     cursor.step_val(&storage);
                                 }
@@ -139,9 +215,9 @@ where
                             }
                             *diff = Tr::R::zero();
                         }
-                    }
+                    });
 
-                    prefixes.retain(|ptd| !ptd.2.is_zero());
+                    prefixes.retain_nonzero();
                 }
             }
         }
@@ -157,4 +233,78 @@ where
         }
 
     }).as_collection()
+}
+
+/// Proposes extensions to a stream of prefixes, treating `arrangement` as a last-writer-wins
+/// key/value map rather than a set/multiset.
+///
+/// `propose_then` sums all diffs for a key across times and emits one output per distinct
+/// value, which is correct when `arrangement` holds a set or multiset of values per key. That
+/// is the wrong behaviour for an arrangement that models a changing key-to-value map (each key
+/// has exactly one current value, maintained by retracting the prior value and asserting the
+/// new one): summing over every historical value would double-count instead of reporting the
+/// single current one. This variant walks the cursor's values for a key and, assuming the
+/// upsert invariant that at most one value has a non-zero accumulated multiplicity at `time`,
+/// emits exactly that `(prefix, value)` pair and stops. A prefix at a time after a key's value
+/// has changed is paired with the new value, because the old value's accumulated multiplicity at
+/// that time is zero. Output is produced only for arriving prefixes: a later change to a key's
+/// value in `arrangement` does not retract proposals already made for earlier prefixes.
+///
+/// Prefixes are held in a `VecStash` while their time is incomplete. For workloads with very
+/// large numbers of in-flight prefixes and an expensive-to-move `P`, see
+/// [`propose_upsert_with_stash`], which takes the stash implementation as a type parameter.
+pub fn propose_upsert<G, Tr, F, P>(
+    prefixes: &Collection<G, P, Tr::R>,
+    arrangement: Arranged<G, Tr>,
+    key_selector: F,
+) -> Collection<G, (P, Tr::Val), Tr::R>
+where
+    G: Scope,
+    G::Timestamp: Lattice,
+    Tr: TraceReader<Time=G::Timestamp>+Clone+'static,
+    Tr::Key: Ord+Hashable+Default,
+    Tr::Val: Clone,
+    Tr::Batch: BatchReader<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::Cursor: Cursor<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::R: Monoid+Mul<Output = Tr::R>+ExchangeData,
+    F: Fn(&P, &mut Tr::Key)+Clone+'static,
+    P: ExchangeData,
+{
+    propose_upsert_with_stash::<_, _, _, _, VecStash<P, G::Timestamp, Tr::R>>(
+        prefixes,
+        arrangement,
+        key_selector,
+    )
+}
+
+/// Like [`propose_upsert`], but takes the per-capability prefix stash implementation as a type
+/// parameter `St: PrefixStash`, mirroring [`propose_then_with_stash`].
+pub fn propose_upsert_with_stash<G, Tr, F, P, St>(
+    prefixes: &Collection<G, P, Tr::R>,
+    arrangement: Arranged<G, Tr>,
+    key_selector: F,
+) -> Collection<G, (P, Tr::Val), Tr::R>
+where
+    G: Scope,
+    G::Timestamp: Lattice,
+    Tr: TraceReader<Time=G::Timestamp>+Clone+'static,
+    Tr::Key: Ord+Hashable+Default,
+    Tr::Val: Clone,
+    Tr::Batch: BatchReader<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::Cursor: Cursor<Tr::Key, Tr::Val, Tr::Time, Tr::R>,
+    Tr::R: Monoid+Mul<Output = Tr::R>+ExchangeData,
+    F: Fn(&P, &mut Tr::Key)+Clone+'static,
+    P: ExchangeData,
+    St: PrefixStash<P, G::Timestamp, Tr::R>+'static,
+{
+    // an upsert key has at most one value with non-zero accumulated multiplicity at `time`, so
+    // stop the walk as soon as it is found instead of visiting the rest of the key's values.
+    propose_cursor_walk::<_, _, _, _, _, _, St>(
+        prefixes,
+        arrangement,
+        key_selector,
+        |prefix: &P, value: &Tr::Val| (prefix.clone(), value.clone()),
+        "ProposeUpsert",
+        true,
+    )
 }
\ No newline at end of file
@@ -0,0 +1,208 @@
+//! Storage strategies for the prefixes that delta-join operators stash per capability.
+//!
+//! `propose`/`propose_then`/`propose_then_with_stash` and `propose_upsert` (via
+//! `propose_upsert_with_stash`), in the sibling `propose.rs`, retain in-flight
+//! `(prefix, time, diff)` triples in a per-capability stash until their time is complete, then
+//! re-sort the stash by a derived key before each pass over the arrangement's cursor.
+//! [`VecStash`] is the straightforward `Vec`-based implementation used by default. [`RegionStash`]
+//! instead keeps `P`, `Time` and `R` in three parallel `Vec`s and sorts a `Vec<usize>` of indices
+//! into them, so that sorting and compaction move 8-byte indices around instead of `(P, Time, R)`
+//! triples. Each `P` is still one heap allocation per `push`, exactly as in `VecStash` — this is
+//! *not* the columnar/flat-buffer arena (`P` serialized into one contiguous byte region) that
+//! would avoid that allocation too, so `RegionStash` doesn't reduce per-tuple allocation pressure
+//! the way its name might suggest. All it buys is avoiding the move of `P` itself during sorting
+//! and compaction, which only matters when `P` is large or expensive to move (e.g. an inline
+//! `[V; N]`-style prefix); for a `P` that is already pointer-sized to move, like the `Vec<V>`
+//! prefixes `crate::plan` builds, moving the whole triple is already cheap and `RegionStash` buys
+//! nothing over `VecStash`. Use it only when `P` itself is the expensive part to relocate.
+//!
+//! `count` and `validate_then` (in `count.rs`/`validate.rs`) do not go through this trait yet:
+//! they stash plain `Vec<(P, Time, R)>`s directly. Their per-key logic (the running-minimum count,
+//! the semijoin accumulation) is simple enough that they haven't needed a pluggable backing store;
+//! migrating them onto [`PrefixStash`] is future work, not something already wired up here.
+
+use std::cmp::Ordering;
+
+use differential_dataflow::difference::Monoid;
+
+/// A container that stashes `(P, Time, R)` triples for later, sorted, traversal.
+pub trait PrefixStash<P, Time, R> {
+    /// Creates an empty stash.
+    fn new() -> Self;
+    /// Appends a triple to the stash.
+    fn push(&mut self, prefix: P, time: Time, diff: R);
+    /// Sorts the stashed triples by a comparator over their prefixes.
+    fn sort_by(&mut self, cmp: impl FnMut(&P, &P) -> Ordering);
+    /// Visits each stashed triple in its current (sorted) order, allowing the diff to be
+    /// updated in place, e.g. zeroed once it has been fully processed.
+    fn for_each_mut(&mut self, logic: impl FnMut(&P, &Time, &mut R));
+    /// Drops triples whose diff has been fully consumed.
+    fn retain_nonzero(&mut self) where R: Monoid;
+    /// Returns `true` if the stash holds no triples.
+    fn is_empty(&self) -> bool;
+}
+
+/// The default prefix stash: a `Vec` of owned `(P, Time, R)` triples, sorted in place.
+pub struct VecStash<P, Time, R> {
+    elements: Vec<(P, Time, R)>,
+}
+
+impl<P, Time, R> PrefixStash<P, Time, R> for VecStash<P, Time, R> {
+    fn new() -> Self {
+        VecStash { elements: Vec::new() }
+    }
+    fn push(&mut self, prefix: P, time: Time, diff: R) {
+        self.elements.push((prefix, time, diff));
+    }
+    fn sort_by(&mut self, mut cmp: impl FnMut(&P, &P) -> Ordering) {
+        self.elements.sort_by(|x, y| cmp(&x.0, &y.0));
+    }
+    fn for_each_mut(&mut self, mut logic: impl FnMut(&P, &Time, &mut R)) {
+        for (prefix, time, diff) in self.elements.iter_mut() {
+            logic(prefix, time, diff);
+        }
+    }
+    fn retain_nonzero(&mut self) where R: Monoid {
+        self.elements.retain(|(_, _, diff)| !diff.is_zero());
+    }
+    fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
+
+/// A region-backed prefix stash: `P`, `Time` and `R` live in their own backing `Vec`s, and
+/// sorting reorders a `Vec<usize>` of indices into those regions rather than the triples
+/// themselves. `P` is still allocated exactly as `VecStash` allocates it (this is not a flat
+/// byte-arena); what this buys instead is that sorting and compaction move a `usize` index rather
+/// than a `(P, Time, R)` triple, at the cost of an extra indirection on lookup. See the module
+/// doc for when that trade is actually worth it.
+pub struct RegionStash<P, Time, R> {
+    prefixes: Vec<P>,
+    times: Vec<Time>,
+    diffs: Vec<R>,
+    order: Vec<usize>,
+    // Scratch buffer for `retain_nonzero`'s old-index -> new-index remap, kept as a field (rather
+    // than a fresh `Vec` per call) so the backing allocation is reused across invocations.
+    remap: Vec<usize>,
+}
+
+impl<P, Time, R> PrefixStash<P, Time, R> for RegionStash<P, Time, R> {
+    fn new() -> Self {
+        RegionStash { prefixes: Vec::new(), times: Vec::new(), diffs: Vec::new(), order: Vec::new(), remap: Vec::new() }
+    }
+    fn push(&mut self, prefix: P, time: Time, diff: R) {
+        let index = self.prefixes.len();
+        self.prefixes.push(prefix);
+        self.times.push(time);
+        self.diffs.push(diff);
+        self.order.push(index);
+    }
+    fn sort_by(&mut self, mut cmp: impl FnMut(&P, &P) -> Ordering) {
+        let prefixes = &self.prefixes;
+        self.order.sort_by(|&x, &y| cmp(&prefixes[x], &prefixes[y]));
+    }
+    fn for_each_mut(&mut self, mut logic: impl FnMut(&P, &Time, &mut R)) {
+        for &index in self.order.iter() {
+            logic(&self.prefixes[index], &self.times[index], &mut self.diffs[index]);
+        }
+    }
+    fn retain_nonzero(&mut self) where R: Monoid {
+        // Compact the regions in place: walk the surviving triples forward, swapping each down
+        // into the next free slot, then truncate off the tail instead of draining into fresh
+        // `Vec`s. `remap` (old index -> new index, `usize::MAX` for a dropped triple) is a field
+        // reused across calls so this allocates nothing once its capacity has settled.
+        let len = self.prefixes.len();
+        self.remap.clear();
+        self.remap.resize(len, usize::MAX);
+
+        let mut write = 0;
+        for read in 0..len {
+            if !self.diffs[read].is_zero() {
+                if write != read {
+                    self.prefixes.swap(write, read);
+                    self.times.swap(write, read);
+                    self.diffs.swap(write, read);
+                }
+                self.remap[read] = write;
+                write += 1;
+            }
+        }
+        self.prefixes.truncate(write);
+        self.times.truncate(write);
+        self.diffs.truncate(write);
+
+        let remap = &self.remap;
+        self.order.retain(|&index| remap[index] != usize::MAX);
+        for index in self.order.iter_mut() {
+            *index = remap[*index];
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PrefixStash, VecStash, RegionStash};
+
+    /// Pushes `input` into a fresh `St`, sorts it by prefix, and reads back the `(prefix, time,
+    /// diff)` triples in their sorted order.
+    fn sorted<St: PrefixStash<i32, u64, isize>>(input: &[(i32, u64, isize)]) -> Vec<(i32, u64, isize)> {
+        let mut stash = St::new();
+        for &(prefix, time, diff) in input {
+            stash.push(prefix, time, diff);
+        }
+        stash.sort_by(|a, b| a.cmp(b));
+        let mut out = Vec::new();
+        stash.for_each_mut(|prefix, time, diff| out.push((*prefix, *time, *diff)));
+        out
+    }
+
+    #[test]
+    fn region_and_vec_stash_sort_identically() {
+        let input = [(3, 0, 1), (1, 0, 1), (2, 0, 1), (1, 0, 2), (0, 0, 1)];
+        assert_eq!(sorted::<VecStash<_, _, _>>(&input), sorted::<RegionStash<_, _, _>>(&input));
+    }
+
+    #[test]
+    fn region_and_vec_stash_retain_nonzero_identically() {
+        let input = [(1, 0, 1), (2, 0, 0), (3, 0, 1), (4, 0, 0), (5, 0, 1), (6, 0, 0)];
+
+        let mut vec_stash: VecStash<i32, u64, isize> = VecStash::new();
+        let mut region_stash: RegionStash<i32, u64, isize> = RegionStash::new();
+        for &(prefix, time, diff) in &input {
+            vec_stash.push(prefix, time, diff);
+            region_stash.push(prefix, time, diff);
+        }
+        vec_stash.sort_by(|a, b| a.cmp(b));
+        region_stash.sort_by(|a, b| a.cmp(b));
+
+        vec_stash.retain_nonzero();
+        region_stash.retain_nonzero();
+
+        let mut vec_remaining = Vec::new();
+        vec_stash.for_each_mut(|prefix, time, diff| vec_remaining.push((*prefix, *time, *diff)));
+        let mut region_remaining = Vec::new();
+        region_stash.for_each_mut(|prefix, time, diff| region_remaining.push((*prefix, *time, *diff)));
+
+        assert_eq!(vec_remaining, region_remaining);
+        assert_eq!(vec_remaining, vec![(1, 0, 1), (3, 0, 1), (5, 0, 1)]);
+        assert!(!vec_stash.is_empty());
+        assert!(!region_stash.is_empty());
+    }
+
+    #[test]
+    fn region_and_vec_stash_empty_after_full_retain() {
+        let mut vec_stash: VecStash<i32, u64, isize> = VecStash::new();
+        let mut region_stash: RegionStash<i32, u64, isize> = RegionStash::new();
+        vec_stash.push(1, 0, 0);
+        region_stash.push(1, 0, 0);
+
+        vec_stash.retain_nonzero();
+        region_stash.retain_nonzero();
+
+        assert!(vec_stash.is_empty());
+        assert!(region_stash.is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+//! A small planner that compiles a declarative multiway join into the count/propose/validate
+//! chain provided by [`crate::operators`].
+//!
+//! This is a minimal, Hector-style variable-at-a-time planner: given a set of binary relations
+//! (each binding two attributes) and an order in which to bind attributes, [`DeltaQuery::render`]
+//! wires up, for every attribute after the first two, one `count` per relation that could extend
+//! the prefix by that attribute, a single `propose` driven by whichever relation counted the
+//! fewest extensions, and a `validate` for every other constraining relation. This keeps the
+//! dataflow worst-case optimal without hand-wiring the operators in `operators` for every join.
+//!
+//! Each relation must be supplied in two arranged forms: `extend`, keyed by the attribute it
+//! binds first (used by `count` and `propose`), and `validate`, keyed by the *pair* of attributes
+//! it binds (used by `validate`, which is a semijoin on its arrangement's key). This mirrors the
+//! extension index / validation index split used by worst-case-optimal join engines generally.
+
+use std::marker::PhantomData;
+
+use timely::dataflow::Scope;
+
+use differential_dataflow::{Collection, ExchangeData, Hashable};
+use differential_dataflow::lattice::Lattice;
+use differential_dataflow::operators::arrange::Arranged;
+use differential_dataflow::trace::{Cursor, TraceReader, BatchReader};
+
+use crate::operators::{count, propose, validate_then};
+
+/// A binary relation participating in a [`DeltaQuery`], arranged both for extension and for
+/// validation. `attributes.0` is the attribute read from `extend`'s key; `attributes.1` is the
+/// attribute read from `extend`'s value (and the second half of `validate`'s key).
+pub struct Relation<G, Tr, Tr2>
+where
+    G: Scope,
+    Tr: TraceReader<Time=G::Timestamp>+Clone+'static,
+    Tr2: TraceReader<Time=G::Timestamp>+Clone+'static,
+{
+    extend: Arranged<G, Tr>,
+    validate: Arranged<G, Tr2>,
+    attributes: (usize, usize),
+}
+
+/// A builder for a worst-case-optimal multiway join over binary relations.
+///
+/// ```text
+/// DeltaQuery::new()
+///     .add_relation(edges_by_src, edges_by_pair, (x, y))
+///     .add_relation(edges_by_src, edges_by_pair, (y, z))
+///     .add_relation(edges_by_src, edges_by_pair, (z, x))
+///     .order(&[x, y, z])
+///     .render()
+/// ```
+pub struct DeltaQuery<G, Tr, Tr2, V>
+where
+    G: Scope,
+    Tr: TraceReader<Time=G::Timestamp>+Clone+'static,
+    Tr2: TraceReader<Time=G::Timestamp>+Clone+'static,
+{
+    relations: Vec<Relation<G, Tr, Tr2>>,
+    order: Vec<usize>,
+    marker: PhantomData<V>,
+}
+
+impl<G, Tr, Tr2, V> DeltaQuery<G, Tr, Tr2, V>
+where
+    G: Scope,
+    G::Timestamp: Lattice,
+    V: ExchangeData+Ord+Hashable+Default,
+    Tr: TraceReader<Time=G::Timestamp, Key=V, Val=V, R=isize>+Clone+'static,
+    Tr::Batch: BatchReader<V, V, G::Timestamp, isize>,
+    Tr::Cursor: Cursor<V, V, G::Timestamp, isize>,
+    Tr2: TraceReader<Time=G::Timestamp, Key=(V,V), Val=(), R=isize>+Clone+'static,
+    Tr2::Batch: BatchReader<(V,V), (), G::Timestamp, isize>,
+    Tr2::Cursor: Cursor<(V,V), (), G::Timestamp, isize>,
+{
+    /// Creates an empty query with no relations and no attribute order.
+    pub fn new() -> Self {
+        DeltaQuery { relations: Vec::new(), order: Vec::new(), marker: PhantomData }
+    }
+
+    /// Adds a relation binding `attributes`, arranged both for extension (`extend`, keyed by
+    /// `attributes.0`) and for validation (`validate`, keyed by the pair `attributes`).
+    pub fn add_relation(mut self, extend: Arranged<G, Tr>, validate: Arranged<G, Tr2>, attributes: (usize, usize)) -> Self {
+        self.relations.push(Relation { extend, validate, attributes });
+        self
+    }
+
+    /// Sets the order in which attributes are bound. The first two attributes must be bound
+    /// together by some relation added with exactly that pair.
+    pub fn order(mut self, order: &[usize]) -> Self {
+        self.order = order.to_vec();
+        self
+    }
+
+    /// Compiles and renders the join, producing one fully-bound attribute vector (in `order`)
+    /// per matching tuple.
+    ///
+    /// Each attribute after the first two is introduced by the relations with `attributes.1 ==
+    /// attr` whose `attributes.0` is already bound (counted, proposed and validated as described
+    /// above). Any other relation whose *both*
+    /// attributes become bound without ever introducing a new one — the edge that closes a
+    /// cycle back to an earlier attribute, for instance — never gets to run as a `count`/`propose`
+    /// constraint, but still has to hold. Those are applied as a plain `validate_then` semijoin
+    /// as soon as their second attribute is bound, using the relation's pair-keyed `validate`
+    /// arrangement.
+    pub fn render(self) -> Collection<G, Vec<V>, isize> {
+        let DeltaQuery { relations, order, .. } = self;
+        assert!(order.len() >= 2, "a delta query needs at least two attributes to bind");
+
+        let mut used = vec![false; relations.len()];
+
+        let (seed_index, seed_relation) = relations.iter().enumerate()
+            .find(|(_, relation)| relation.attributes == (order[0], order[1]))
+            .expect("no relation binds the first two attributes of the order");
+        used[seed_index] = true;
+
+        let mut prefixes = seed_relation.extend.clone()
+            .as_collection(|k: &V, v: &V| vec![k.clone(), v.clone()]);
+
+        for step in 2..order.len() {
+            let attr = order[step];
+            let bound = &order[..step];
+
+            let constraints: Vec<(usize, &Relation<G, Tr, Tr2>)> = relations.iter().enumerate()
+                .filter(|(i, relation)| !used[*i] && relation.attributes.1 == attr && bound.contains(&relation.attributes.0))
+                .collect();
+            assert!(!constraints.is_empty(), "no relation found to bind attribute {}", attr);
+            for &(i, _) in &constraints { used[i] = true; }
+
+            let position_of = |a: usize| bound.iter().position(|&b| b == a).unwrap();
+
+            // count every constraining relation, keeping only the smallest extension count and
+            // the relation index (within `constraints`) that produced it.
+            let mut counted = prefixes.map(|prefix| (prefix, usize::MAX, usize::MAX));
+            for (index, &(_, relation)) in constraints.iter().enumerate() {
+                let position = position_of(relation.attributes.0);
+                counted = count(&counted, relation.extend.clone(), move |p: &Vec<V>| p[position].clone(), index);
+            }
+
+            // the least-selective relation proposes extensions; every other constraint validates.
+            let mut extended: Option<Collection<G, Vec<V>, isize>> = None;
+            for (index, &(_, relation)) in constraints.iter().enumerate() {
+                let winners = counted
+                    .filter(move |triple: &(Vec<V>, usize, usize)| triple.2 == index)
+                    .map(|triple| triple.0);
+
+                let position = position_of(relation.attributes.0);
+                let mut validated = propose(&winners, relation.extend.clone(), move |p: &Vec<V>| p[position].clone())
+                    .map(|(mut prefix, value)| { prefix.push(value); prefix });
+
+                for (other_index, &(_, other_relation)) in constraints.iter().enumerate() {
+                    if other_index != index {
+                        let other_position = position_of(other_relation.attributes.0);
+                        let new_position = step;
+                        validated = validate_then(&validated, other_relation.validate.clone(), move |p: &Vec<V>| {
+                            (p[other_position].clone(), p[new_position].clone())
+                        });
+                    }
+                }
+
+                extended = Some(match extended {
+                    Some(e) => e.concat(&validated),
+                    None => validated,
+                });
+            }
+
+            let mut prefixes_next = extended.expect("at least one constraint");
+
+            // close out any relation that binds two already-bound attributes without having
+            // introduced either of them as new (e.g. the edge closing a triangle).
+            let bound_now = &order[..=step];
+            for (i, relation) in relations.iter().enumerate() {
+                if !used[i] && bound_now.contains(&relation.attributes.0) && bound_now.contains(&relation.attributes.1) {
+                    used[i] = true;
+                    let position_a = bound_now.iter().position(|&b| b == relation.attributes.0).unwrap();
+                    let position_b = bound_now.iter().position(|&b| b == relation.attributes.1).unwrap();
+                    prefixes_next = validate_then(&prefixes_next, relation.validate.clone(), move |p: &Vec<V>| {
+                        (p[position_a].clone(), p[position_b].clone())
+                    });
+                }
+            }
+
+            prefixes = prefixes_next;
+        }
+
+        assert!(used.iter().all(|&u| u), "every added relation must constrain some attribute in the order");
+        prefixes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use timely::dataflow::operators::probe::Handle;
+    use timely::dataflow::operators::{Inspect, Probe};
+
+    use differential_dataflow::input::InputSession;
+    use differential_dataflow::operators::Count;
+    use differential_dataflow::operators::arrange::{ArrangeByKey, ArrangeBySelf};
+
+    use super::DeltaQuery;
+
+    /// Runs a `DeltaQuery` built from `triples` (each a pair of attribute ids, as in `order`) over a
+    /// fixed edge relation and returns how many fully-bound tuples it produces.
+    fn run_query(edges: Vec<(u32, u32)>, order: Vec<usize>, triples: Vec<(usize, usize)>) -> isize {
+        let total = Rc::new(RefCell::new(0isize));
+        let total_inner = total.clone();
+
+        timely::execute_directly(move |worker| {
+
+            let mut probe = Handle::new();
+            let mut input: InputSession<_, (u32, u32), isize> = InputSession::new();
+
+            worker.dataflow(|scope| {
+                let edges = input.to_collection(scope);
+                let by_key = edges.arrange_by_key();
+                let by_pair = edges.arrange_by_self();
+
+                let mut query = DeltaQuery::new();
+                for &attrs in &triples {
+                    query = query.add_relation(by_key.clone(), by_pair.clone(), attrs);
+                }
+
+                query.order(&order).render().count()
+                    .inspect(move |((_tuple, count), _time, diff)| *total_inner.borrow_mut() += count * diff)
+                    .probe_with(&mut probe);
+            });
+
+            for edge in edges {
+                input.insert(edge);
+            }
+            input.advance_to(1);
+            input.flush();
+            worker.step_while(|| probe.less_than(input.time()));
+        });
+
+        *total.borrow()
+    }
+
+    #[test]
+    fn path_query_on_a_triangle() {
+        // x -> y -> z, over a three-cycle: each vertex has exactly one outgoing edge, so the
+        // two-hop path query (x,y), (y,z) binds exactly one (x,y,z) tuple per starting vertex.
+        let edges = vec![(0, 1), (1, 2), (2, 0)];
+        let order = vec![0, 1, 2];
+        let triples = vec![(0, 1), (1, 2)];
+        assert_eq!(run_query(edges, order, triples), 3);
+    }
+
+    #[test]
+    fn triangle_query_on_a_triangle() {
+        // the same three-cycle, but closed into a triangle by also constraining (z, x): every
+        // vertex participates in exactly one triangle (itself, its successor, its successor's
+        // successor), so three fully-bound (x,y,z) tuples are expected.
+        let edges = vec![(0, 1), (1, 2), (2, 0)];
+        let order = vec![0, 1, 2];
+        let triples = vec![(0, 1), (1, 2), (2, 0)];
+        assert_eq!(run_query(edges, order, triples), 3);
+    }
+}